@@ -1,6 +1,8 @@
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueEnum};
 use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::Serialize;
 use std::{fs, path::Path};
 use tokio::fs as tokio_fs;
 
@@ -22,6 +24,76 @@ struct Cli {
 
     #[arg(long, default_value_t = true)]
     enable_lower_layers: bool,
+
+    /// Descriptor format. `auto` sniffs the file contents.
+    #[arg(long, value_enum, default_value_t = Format::Auto)]
+    format: Format,
+
+    /// Encoding of the composited layers.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Png)]
+    output_format: OutputFormat,
+
+    /// Lossy quality 0..=100 for webp/jpeg/avif (ignored for png).
+    #[arg(long, default_value_t = 80)]
+    quality: u8,
+
+    /// Background `RRGGBB` used to flatten alpha for formats without it (jpeg).
+    #[arg(long, default_value = "000000")]
+    background: String,
+
+    /// Base URL for remote tiles. When set, tiles missing from `--tex-dir` are
+    /// fetched from `{base_url}/{tile_name}.{ext}` (`ext` being the descriptor's
+    /// tile format) and cached locally.
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Maximum concurrent tile downloads.
+    #[arg(long, default_value_t = 16)]
+    fetch_concurrency: usize,
+
+    /// Composite one tile-row band at a time, streaming scanlines straight to a
+    /// PNG encoder so the full canvas is never materialised. Auto-enabled when
+    /// the canvas would exceed `--stream-threshold`. PNG output only.
+    #[arg(long, default_value_t = false)]
+    stream: bool,
+
+    /// Canvas byte budget (`w * h * 4`) above which streaming auto-enables.
+    #[arg(long, default_value_t = 1 << 30)]
+    stream_threshold: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Png,
+    Webp,
+    Jpeg,
+    Avif,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    /// Whether the encoder preserves an alpha channel. Non-alpha formats need
+    /// the image flattened onto a background colour first.
+    fn has_alpha(self) -> bool {
+        !matches!(self, OutputFormat::Jpeg)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Auto,
+    Malie,
+    DeepZoom,
+    Zoomify,
+    Iiif,
 }
 
 #[allow(dead_code)]
@@ -36,54 +108,465 @@ struct DziLayer {
 struct DziFile {
     width: u32,
     height: u32,
+    /// Tile file extension (no leading dot). Most backends emit `png`; DeepZoom
+    /// honours the descriptor's `Format` attribute so `.jpg` sources load.
+    tile_format: String,
+    /// Scale of layer 0 relative to full resolution. Malie stores a 2× upscale as
+    /// layer 0 (layer 1 is full res), so it reports `2.0`; the standard backends
+    /// put full resolution at layer 0 and report `1.0`. Each later layer halves.
+    base_scale: f64,
     layers: Vec<DziLayer>,
 }
 
-async fn parse_dzi(file_path: &Path) -> Result<DziFile> {
+/// Machine-readable summary of everything `run` produced, written as
+/// `manifest.json` so downstream viewers and build pipelines don't have to
+/// scrape the output directory.
+#[derive(Serialize, Debug)]
+struct Manifest {
+    groups: Vec<GroupManifest>,
+}
+
+#[derive(Serialize, Debug)]
+struct GroupManifest {
+    group: String,
+    descriptor: String,
+    width: u32,
+    height: u32,
+    layers: Vec<LayerManifest>,
+}
+
+#[derive(Serialize, Debug)]
+struct LayerManifest {
+    layer_index: usize,
+    target_w: u32,
+    target_h: u32,
+    scale: f64,
+    /// Relative path of the produced file, absent when the layer was skipped or
+    /// had no tiles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    skipped: bool,
+}
+
+/// A descriptor backend turns a raw descriptor file into the engine-neutral
+/// [`DziFile`] that [`compose_layer`] consumes. Adding a new tiled-image format
+/// means adding one implementor — the composition path stays unchanged.
+trait Descriptor {
+    fn parse(&self, content: &str) -> Result<DziFile>;
+}
+
+async fn parse_dzi(file_path: &Path, format: Format) -> Result<DziFile> {
     let content = tokio_fs::read_to_string(file_path).await?;
-    let mut lines = content.lines();
-
-    let _format_line = lines.next();
-    let size_line = lines.next().context("No size line in DZI file")?;
-    let (img_width, img_height) = {
-        let parts: Vec<u32> = size_line
-            .split(',')
-            .map(|s| s.trim().parse().unwrap())
-            .collect();
-        (parts[0], parts[1])
+    select_descriptor(format, &content).parse(&content)
+}
+
+/// Pick the backend for `format`, sniffing the contents when `Auto`.
+fn select_descriptor(format: Format, content: &str) -> Box<dyn Descriptor> {
+    let format = match format {
+        Format::Auto => detect_format(content),
+        other => other,
     };
+    match format {
+        Format::DeepZoom => Box::new(DeepZoomXml),
+        Format::Zoomify => Box::new(Zoomify),
+        Format::Iiif => Box::new(Iiif),
+        // `Auto` has already been resolved above; malie is the fallback.
+        Format::Malie | Format::Auto => Box::new(Malie),
+    }
+}
 
-    let mut layers = Vec::new();
+fn detect_format(content: &str) -> Format {
+    let trimmed = content.trim_start();
+    if trimmed.starts_with('{') {
+        Format::Iiif
+    } else if trimmed.contains("<IMAGE_PROPERTIES") {
+        Format::Zoomify
+    } else if trimmed.contains("<Image") {
+        Format::DeepZoom
+    } else {
+        Format::Malie
+    }
+}
+
+/// The malie engine's custom CSV-style descriptor: a format line, a
+/// `width,height` size line, then per-layer `cols,rows` headers each followed by
+/// `rows` lines of comma-separated tile names.
+struct Malie;
+
+impl Descriptor for Malie {
+    fn parse(&self, content: &str) -> Result<DziFile> {
+        let mut lines = content.lines();
+
+        let _format_line = lines.next();
+        let size_line = lines.next().context("No size line in DZI file")?;
+        let (img_width, img_height) = {
+            let parts: Vec<u32> = size_line
+                .split(',')
+                .map(|s| s.trim().parse().unwrap())
+                .collect();
+            (parts[0], parts[1])
+        };
+
+        let mut layers = Vec::new();
+
+        let mut iter = lines.peekable();
+        while iter.peek().is_some() {
+            let size_line = iter.next().unwrap();
+            let parts: Vec<usize> = size_line
+                .split(',')
+                .map(|s| s.trim().parse().unwrap())
+                .collect();
+            let (cols, rows) = (parts[0], parts[1]);
+
+            let mut tiles = Vec::new();
+            for _ in 0..rows {
+                let row_line = iter.next().unwrap();
+                let row: Vec<String> =
+                    row_line.split(',').map(|s| s.trim().to_string()).collect();
+                tiles.push(row);
+            }
+
+            layers.push(DziLayer { tiles, rows, cols });
+        }
+
+        Ok(DziFile {
+            width: img_width,
+            height: img_height,
+            tile_format: "png".to_string(),
+            base_scale: 2.0,
+            layers,
+        })
+    }
+}
+
+/// Standard Deep Zoom (`.dzi`/`.xml`): `<Image TileSize Overlap Format>` with a
+/// nested `<Size Width Height>`. Tiles live under `{level}/{col}_{row}.{fmt}`
+/// and levels run `0..=ceil(log2(max(width, height)))`, finest last. The `Format`
+/// attribute selects the tile extension; non-zero `Overlap` is rejected because
+/// the composition path assumes abutting (non-overlapping) tiles.
+struct DeepZoomXml;
 
-    let mut iter = lines.peekable();
-    while iter.peek().is_some() {
-        let size_line = iter.next().unwrap();
-        let parts: Vec<usize> = size_line
-            .split(',')
-            .map(|s| s.trim().parse().unwrap())
+impl Descriptor for DeepZoomXml {
+    fn parse(&self, content: &str) -> Result<DziFile> {
+        let tile_size: u32 = xml_attr(content, "TileSize")
+            .context("DeepZoom descriptor missing TileSize")?
+            .parse()?;
+        let width: u32 = xml_attr(content, "Width")
+            .context("DeepZoom descriptor missing Width")?
+            .parse()?;
+        let height: u32 = xml_attr(content, "Height")
+            .context("DeepZoom descriptor missing Height")?
+            .parse()?;
+        // Default to png to match the other backends when `Format` is absent.
+        let tile_format = xml_attr(content, "Format").unwrap_or("png").to_string();
+        // Overlap shifts each tile's placement by `overlap` px on every interior
+        // edge; the grid-aligned `col * tile_w` placement below can't represent
+        // that, so bail rather than silently misalign the mosaic.
+        let overlap: u32 = xml_attr(content, "Overlap").unwrap_or("0").parse()?;
+        if overlap != 0 {
+            bail!("DeepZoom Overlap={overlap} is unsupported; only non-overlapping tiles (Overlap=0) can be composed");
+        }
+
+        let max_dim = width.max(height) as f64;
+        let max_level = max_dim.log2().ceil() as u32;
+
+        // Emit finest level first so layer 0 is full resolution (`base_scale`
+        // 1.0) and each subsequent layer a half-scale mip.
+        let mut layers = Vec::new();
+        for level in (0..=max_level).rev() {
+            let scale = 2u32.pow(max_level - level);
+            let level_w = width.div_ceil(scale);
+            let level_h = height.div_ceil(scale);
+            let cols = level_w.div_ceil(tile_size) as usize;
+            let rows = level_h.div_ceil(tile_size) as usize;
+
+            let tiles = (0..rows)
+                .map(|row| {
+                    (0..cols)
+                        .map(|col| format!("{level}/{col}_{row}"))
+                        .collect()
+                })
+                .collect();
+
+            layers.push(DziLayer { tiles, rows, cols });
+        }
+
+        Ok(DziFile {
+            width,
+            height,
+            tile_format,
+            base_scale: 1.0,
+            layers,
+        })
+    }
+}
+
+/// Zoomify `ImageProperties.xml`: `WIDTH`, `HEIGHT`, `TILESIZE`, `NUMTILES`.
+/// Tiles are grouped into `TileGroup{n}` folders of 256 tiles each, addressed as
+/// `{level}-{col}-{row}`.
+struct Zoomify;
+
+impl Descriptor for Zoomify {
+    fn parse(&self, content: &str) -> Result<DziFile> {
+        let width: u32 = xml_attr(content, "WIDTH")
+            .context("Zoomify descriptor missing WIDTH")?
+            .parse()?;
+        let height: u32 = xml_attr(content, "HEIGHT")
+            .context("Zoomify descriptor missing HEIGHT")?
+            .parse()?;
+        let tile_size: u32 = xml_attr(content, "TILESIZE")
+            .context("Zoomify descriptor missing TILESIZE")?
+            .parse()?;
+
+        // Count levels: halve the image until it fits in a single tile.
+        let mut dims = Vec::new();
+        let (mut w, mut h) = (width, height);
+        loop {
+            dims.push((w, h));
+            if w <= tile_size && h <= tile_size {
+                break;
+            }
+            w = w.div_ceil(2);
+            h = h.div_ceil(2);
+        }
+        // `dims` is finest-first; levels number the other way (0 = coarsest).
+        let num_levels = dims.len();
+
+        // Per-level tile grid indexed by Zoomify level (0 = coarsest). `dims` is
+        // finest-first, so reverse it to line levels up ascending.
+        let level_dims: Vec<(usize, usize)> = dims
+            .iter()
+            .rev()
+            .map(|&(lw, lh)| (lw.div_ceil(tile_size) as usize, lh.div_ceil(tile_size) as usize))
             .collect();
-        let (cols, rows) = (parts[0], parts[1]);
 
-        let mut tiles = Vec::new();
-        for _ in 0..rows {
-            let row_line = iter.next().unwrap();
-            let row: Vec<String> = row_line.split(',').map(|s| s.trim().to_string()).collect();
-            tiles.push(row);
+        // Zoomify numbers tiles coarsest-first, row-major within a level, and the
+        // flat index decides the `TileGroup{n}` folder (256 tiles per group).
+        // Precompute each level's starting index so the grouping is correct no
+        // matter which order we emit layers in below.
+        let mut level_start = Vec::with_capacity(num_levels);
+        let mut acc = 0u32;
+        for &(cols, rows) in &level_dims {
+            level_start.push(acc);
+            acc += (cols * rows) as u32;
+        }
+
+        // Cross-check the derived tile total against the descriptor's advertised
+        // `NUMTILES` when present, so a bad level computation fails loudly.
+        if let Some(num_tiles) = xml_attr(content, "NUMTILES") {
+            let num_tiles: u32 = num_tiles.parse()?;
+            if num_tiles != acc {
+                bail!("Zoomify NUMTILES={num_tiles} disagrees with derived tile count {acc}");
+            }
+        }
+
+        let mut layers = Vec::with_capacity(num_levels);
+        for finest_offset in 0..num_levels {
+            let level = num_levels - 1 - finest_offset;
+            let (cols, rows) = level_dims[level];
+            let start = level_start[level];
+
+            let tiles = (0..rows)
+                .map(|row| {
+                    (0..cols)
+                        .map(|col| {
+                            let group = (start + (row * cols + col) as u32) / 256;
+                            format!("TileGroup{group}/{level}-{col}-{row}")
+                        })
+                        .collect()
+                })
+                .collect();
+
+            layers.push(DziLayer { tiles, rows, cols });
         }
 
-        layers.push(DziLayer { tiles, rows, cols });
+        Ok(DziFile {
+            width,
+            height,
+            tile_format: "png".to_string(),
+            base_scale: 1.0,
+            layers,
+        })
     }
+}
 
-    Ok(DziFile {
-        width: img_width,
-        height: img_height,
-        layers,
-    })
+/// IIIF Image API `info.json`: top-level `width`/`height` and a `tiles` array
+/// carrying the tile `width` and supported `scaleFactors`.
+struct Iiif;
+
+impl Descriptor for Iiif {
+    fn parse(&self, content: &str) -> Result<DziFile> {
+        let info: serde_json::Value = serde_json::from_str(content)?;
+
+        let width = info["width"].as_u64().context("IIIF info missing width")? as u32;
+        let height = info["height"]
+            .as_u64()
+            .context("IIIF info missing height")? as u32;
+
+        let tile = info["tiles"]
+            .get(0)
+            .context("IIIF info missing tiles[0]")?;
+        let tile_w = tile["width"].as_u64().context("IIIF tile missing width")? as u32;
+        let tile_h = tile["height"].as_u64().unwrap_or(tile_w as u64) as u32;
+
+        let mut scale_factors: Vec<u32> = tile["scaleFactors"]
+            .as_array()
+            .context("IIIF tile missing scaleFactors")?
+            .iter()
+            .map(|v| v.as_u64().map(|n| n as u32).ok_or_else(|| anyhow!("bad scaleFactor")))
+            .collect::<Result<_>>()?;
+        if scale_factors.is_empty() {
+            bail!("IIIF tile has no scaleFactors");
+        }
+        // Finest scale (1) first to match the full-res-first layer ordering.
+        scale_factors.sort_unstable();
+
+        let mut layers = Vec::with_capacity(scale_factors.len());
+        for sf in scale_factors {
+            let region = tile_w * sf;
+            let region_h = tile_h * sf;
+            let cols = width.div_ceil(region) as usize;
+            let rows = height.div_ceil(region_h) as usize;
+
+            let tiles = (0..rows)
+                .map(|row| {
+                    (0..cols)
+                        .map(|col| {
+                            let x = col as u32 * region;
+                            let y = row as u32 * region;
+                            let rw = region.min(width - x);
+                            let rh = region_h.min(height - y);
+                            // Rendered size is the region downscaled by the factor,
+                            // so edge columns request their true (narrower) width
+                            // rather than a full tile the server would stretch.
+                            let request_w = rw.div_ceil(sf);
+                            // IIIF Image API request path (region/size/rotation/quality).
+                            format!("{x},{y},{rw},{rh}/{request_w},/0/default")
+                        })
+                        .collect()
+                })
+                .collect();
+
+            layers.push(DziLayer { tiles, rows, cols });
+        }
+
+        Ok(DziFile {
+            width,
+            height,
+            tile_format: "png".to_string(),
+            base_scale: 1.0,
+            layers,
+        })
+    }
+}
+
+/// Pull the value of a flat XML attribute (`Name="value"` or `Name='value'`)
+/// from a small descriptor document. The descriptors we support are flat enough
+/// that a full parser would be overkill.
+fn xml_attr<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let key = content.match_indices(name).find_map(|(idx, _)| {
+        let after = &content[idx + name.len()..];
+        let after = after.trim_start();
+        after.strip_prefix('=').map(|rest| rest.trim_start())
+    })?;
+    let quote = key.chars().next()?;
+    if quote != '"' && quote != '\'' {
+        return None;
+    }
+    key[1..].split(quote).next()
 }
 fn load_tile(path: &Path) -> Result<DynamicImage> {
     image::open(path).with_context(|| format!("Failed to open tile: {:?}", path))
 }
 
+/// Download every tile referenced by `dzi` that isn't already cached under
+/// `tex_dir`, with bounded concurrency, and decode+re-save each as a local
+/// `{tex_dir}/{name}.{ext}` (the descriptor's tile format) so subsequent runs
+/// are fully offline.
+async fn prefetch_tiles(
+    client: &reqwest::Client,
+    base_url: &str,
+    tex_dir: &Path,
+    dzi: &DziFile,
+    concurrency: usize,
+) -> Result<()> {
+    use futures::stream::StreamExt;
+
+    let base_url = base_url.trim_end_matches('/');
+    let ext = dzi.tile_format.as_str();
+
+    // Dedup tile names, then keep only the ones not already on disk.
+    let mut names: Vec<&str> = dzi
+        .layers
+        .iter()
+        .flat_map(|layer| layer.tiles.iter().flatten())
+        .map(|s| s.as_str())
+        .filter(|s| !s.is_empty())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let missing: Vec<&str> = names
+        .into_iter()
+        .filter(|name| !tex_dir.join(format!("{name}.{ext}")).exists())
+        .collect();
+
+    let mut stream = futures::stream::iter(missing.into_iter().map(|name| {
+        let url = format!("{base_url}/{name}.{ext}");
+        async move {
+            let bytes = fetch_with_retry(client, &url)
+                .await
+                .with_context(|| format!("Failed to fetch tile {url}"))?;
+            // Validate and normalise by decoding before caching.
+            let img = image::load_from_memory(&bytes)
+                .with_context(|| format!("Failed to decode tile {url}"))?;
+            let cache_path = tex_dir.join(format!("{name}.{ext}"));
+            if let Some(parent) = cache_path.parent() {
+                tokio_fs::create_dir_all(parent).await?;
+            }
+            img.save(&cache_path)
+                .with_context(|| format!("Failed to cache tile {:?}", cache_path))?;
+            Ok::<(), anyhow::Error>(())
+        }
+    }))
+    .buffer_unordered(concurrency.max(1));
+
+    while let Some(result) = stream.next().await {
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Fetch `url`, retrying a few times on transient (network / 5xx) failures with
+/// a short linear backoff.
+async fn fetch_with_retry(client: &reqwest::Client, url: &str) -> Result<Vec<u8>> {
+    const MAX_ATTEMPTS: u32 = 3;
+
+    let mut last_err = None;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match client.get(url).send().await.and_then(|r| r.error_for_status()) {
+            Ok(resp) => match resp.bytes().await {
+                Ok(bytes) => return Ok(bytes.to_vec()),
+                Err(err) => last_err = Some(err),
+            },
+            Err(err) => {
+                // A 4xx won't change on retry, so fail immediately.
+                if err.status().is_some_and(|s| s.is_client_error()) {
+                    return Err(err.into());
+                }
+                last_err = Some(err);
+            }
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(std::time::Duration::from_millis(200 * attempt as u64)).await;
+        }
+    }
+
+    Err(anyhow!(last_err.unwrap()))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn compose_layer(
     tiles: &[Vec<String>],
     layer_index: usize,
@@ -92,12 +575,18 @@ fn compose_layer(
     output_path: &Path,
     final_width: u32,
     final_height: u32,
-) -> Result<()> {
+    tile_ext: &str,
+    output_format: OutputFormat,
+    quality: u8,
+    background: Rgba<u8>,
+    stream: bool,
+    stream_threshold: u64,
+) -> Result<bool> {
     if tiles.is_empty() || tiles[0].is_empty() {
-        return Ok(());
+        return Ok(false);
     }
 
-    let first_tile_path = tex_dir.join(format!("{}.png", tiles[0][0]));
+    let first_tile_path = tex_dir.join(format!("{}.{tile_ext}", tiles[0][0]));
     let first_tile = load_tile(&first_tile_path)?;
     let (tile_w, tile_h) = first_tile.dimensions();
 
@@ -106,22 +595,66 @@ fn compose_layer(
     let composed_w = cols as u32 * tile_w;
     let composed_h = rows as u32 * tile_h;
 
+    // Stream band-by-band when asked, or when the full canvas would blow the
+    // byte budget. Streaming only applies to PNG, whose encoder accepts rows
+    // incrementally; other codecs still need the whole image in memory.
+    let canvas_bytes = composed_w as u64 * composed_h as u64 * 4;
+    if output_format == OutputFormat::Png && (stream || canvas_bytes > stream_threshold) {
+        return compose_layer_streaming(
+            tiles,
+            layer_index,
+            group,
+            tex_dir,
+            output_path,
+            tile_w,
+            tile_h,
+            composed_w,
+            final_width.min(composed_w),
+            final_height.min(composed_h),
+            tile_ext,
+        );
+    }
+
+    // Collect the (x, y, tile_name) triples first so the independent decodes can
+    // fan out across cores; `load_tile` is pure, so the only shared state is the
+    // final `canvas`, which we touch from a single cheap sequential pass below.
+    let placements: Vec<(u32, u32, &str)> = tiles
+        .iter()
+        .enumerate()
+        .flat_map(|(y, row)| {
+            row.iter()
+                .enumerate()
+                .filter(|(_, name)| !name.is_empty())
+                .map(move |(x, name)| (x as u32, y as u32, name.as_str()))
+        })
+        .collect();
+
+    // Decode in bounded batches rather than collecting every tile up front: the
+    // baseline decoded one tile at a time, so holding the whole layer decoded
+    // alongside the canvas would roughly double peak memory. Batches of four
+    // tiles per core keep the fan-out without the 2× blow-up.
     let mut canvas = RgbaImage::from_pixel(composed_w, composed_h, Rgba([0, 0, 0, 0]));
+    // Track the real painted extent from each tile's own size: the right/bottom
+    // edge tiles of a non-tile-aligned image are smaller than `tile_w`/`tile_h`,
+    // so `cols * tile_w` overstates the canvas and would otherwise leave a
+    // transparent border. Cropping to the actual content avoids that.
+    let (mut content_w, mut content_h) = (0u32, 0u32);
+    let batch = (rayon::current_num_threads() * 4).max(1);
+    for chunk in placements.chunks(batch) {
+        let decoded: Vec<(u32, u32, RgbaImage)> = chunk
+            .par_iter()
+            .map(|(x, y, tile_name)| {
+                let tile_path = tex_dir.join(format!("{tile_name}.{tile_ext}"));
+                let tile_img = load_tile(&tile_path)?.to_rgba8();
+                Ok((*x, *y, tile_img))
+            })
+            .collect::<Result<_>>()?;
 
-    for (y, row) in tiles.iter().enumerate() {
-        for (x, tile_name) in row.iter().enumerate() {
-            if tile_name.is_empty() {
-                continue;
-            }
-            let tile_path = tex_dir.join(format!("{tile_name}.png"));
-            let tile_img = load_tile(&tile_path)?.to_rgba8();
-
-            image::imageops::overlay(
-                &mut canvas,
-                &tile_img,
-                (x as u32 * tile_w) as i64,
-                (y as u32 * tile_h) as i64,
-            );
+        for (x, y, tile_img) in decoded {
+            let (ox, oy) = (x * tile_w, y * tile_h);
+            content_w = content_w.max(ox + tile_img.width());
+            content_h = content_h.max(oy + tile_img.height());
+            image::imageops::overlay(&mut canvas, &tile_img, ox as i64, oy as i64);
         }
     }
 
@@ -129,17 +662,146 @@ fn compose_layer(
         &canvas,
         0,
         0,
-        final_width.min(composed_w),
-        final_height.min(composed_h),
+        final_width.min(content_w),
+        final_height.min(content_h),
     )
     .to_image();
 
+    let out_dir = output_path.join(group);
+    fs::create_dir_all(&out_dir)?;
+    let out_file = out_dir.join(format!(
+        "layer_{layer_index}.{}",
+        output_format.extension()
+    ));
+    encode_image(&cropped, output_format, quality, background, &out_file)?;
+
+    println!("Composed layer_{layer_index} for group {group}");
+    Ok(true)
+}
+
+/// Streaming counterpart of [`compose_layer`]: composite one tile-row band at a
+/// time into a `composed_w × tile_h` buffer and feed the clamped scanlines
+/// straight to a PNG stream encoder, so peak memory is one band rather than the
+/// whole canvas. The right edge and final band are clamped to
+/// `final_width`/`final_height`.
+#[allow(clippy::too_many_arguments)]
+fn compose_layer_streaming(
+    tiles: &[Vec<String>],
+    layer_index: usize,
+    group: &str,
+    tex_dir: &Path,
+    output_path: &Path,
+    tile_w: u32,
+    tile_h: u32,
+    composed_w: u32,
+    final_width: u32,
+    final_height: u32,
+    tile_ext: &str,
+) -> Result<bool> {
+    use std::io::Write;
+
     let out_dir = output_path.join(group);
     fs::create_dir_all(&out_dir)?;
     let out_file = out_dir.join(format!("layer_{layer_index}.png"));
-    cropped.save(out_file)?;
+
+    let file = std::io::BufWriter::new(fs::File::create(&out_file)?);
+    let mut encoder = png::Encoder::new(file, final_width, final_height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let mut stream = writer.stream_writer()?;
+
+    for (row_index, row) in tiles.iter().enumerate() {
+        let band_top = row_index as u32 * tile_h;
+        if band_top >= final_height {
+            break;
+        }
+        let band_rows = tile_h.min(final_height - band_top);
+
+        // Decode this band's tiles in parallel, then overlay them sequentially.
+        let decoded: Vec<(u32, RgbaImage)> = row
+            .par_iter()
+            .enumerate()
+            .filter(|(_, name)| !name.is_empty())
+            .map(|(x, name)| {
+                let tile_path = tex_dir.join(format!("{name}.{tile_ext}"));
+                Ok((x as u32, load_tile(&tile_path)?.to_rgba8()))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut band = RgbaImage::from_pixel(composed_w, tile_h, Rgba([0, 0, 0, 0]));
+        for (x, tile_img) in decoded {
+            image::imageops::overlay(&mut band, &tile_img, (x * tile_w) as i64, 0);
+        }
+
+        // Emit the valid scanlines, clamped to the final width.
+        for y in 0..band_rows {
+            let start = ((y * composed_w) * 4) as usize;
+            let end = start + (final_width * 4) as usize;
+            stream.write_all(&band.as_raw()[start..end])?;
+        }
+    }
+
+    stream.finish()?;
 
     println!("Composed layer_{layer_index} for group {group}");
+    Ok(true)
+}
+
+/// Parse an `RRGGBB` hex colour into an opaque RGBA pixel.
+fn parse_hex_color(hex: &str) -> Result<Rgba<u8>> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() != 6 {
+        bail!("background colour must be RRGGBB, got `{hex}`");
+    }
+    let r = u8::from_str_radix(&hex[0..2], 16)?;
+    let g = u8::from_str_radix(&hex[2..4], 16)?;
+    let b = u8::from_str_radix(&hex[4..6], 16)?;
+    Ok(Rgba([r, g, b, 255]))
+}
+
+/// Flatten the RGBA image onto `background`, dropping the alpha channel.
+fn flatten(img: &RgbaImage, background: Rgba<u8>) -> image::RgbImage {
+    let [br, bg, bb, _] = background.0;
+    image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *img.get_pixel(x, y);
+        let a = a as u32;
+        let blend = |fg: u8, bg: u8| ((fg as u32 * a + bg as u32 * (255 - a)) / 255) as u8;
+        image::Rgb([blend(r, br), blend(g, bg), blend(b, bb)])
+    })
+}
+
+/// Encode `img` to `out_file` with the selected codec and quality.
+fn encode_image(
+    img: &RgbaImage,
+    format: OutputFormat,
+    quality: u8,
+    background: Rgba<u8>,
+    out_file: &Path,
+) -> Result<()> {
+    match format {
+        OutputFormat::Png => img.save(out_file)?,
+        OutputFormat::Avif => {
+            let file = fs::File::create(out_file)?;
+            let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                file, 4, quality,
+            );
+            img.write_with_encoder(encoder)?;
+        }
+        OutputFormat::Jpeg => {
+            debug_assert!(!format.has_alpha());
+            let rgb = flatten(img, background);
+            let mut file = fs::File::create(out_file)?;
+            let encoder =
+                image::codecs::jpeg::JpegEncoder::new_with_quality(&mut file, quality);
+            rgb.write_with_encoder(encoder)?;
+        }
+        OutputFormat::Webp => {
+            let encoder = webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height());
+            let encoded = encoder.encode(quality as f32);
+            fs::write(out_file, &*encoded)?;
+        }
+    }
     Ok(())
 }
 
@@ -155,6 +817,7 @@ async fn main() {
 
 async fn run() -> Result<()> {
     let cli = Cli::parse();
+    let background = parse_hex_color(&cli.background)?;
     let event_dir = Path::new(&cli.event_dir);
     let tex_dir = event_dir.join(&cli.tex_dir);
     let output_path = event_dir.join(&cli.output_dir);
@@ -163,6 +826,10 @@ async fn run() -> Result<()> {
         fs::remove_dir_all(&output_path)?;
     }
 
+    let client = cli.base_url.as_ref().map(|_| reqwest::Client::new());
+
+    let mut handles = Vec::new();
+
     for entry in fs::read_dir(event_dir)? {
         let entry = entry?;
         let path = entry.path();
@@ -174,30 +841,195 @@ async fn run() -> Result<()> {
         let group = path.file_stem().unwrap().to_string_lossy().to_string();
         println!("Handling {group} ...");
 
-        let dzi = parse_dzi(&path).await?;
-
-        for (i, layer) in dzi.layers.iter().enumerate() {
-            if i > 1 && !cli.enable_lower_layers {
-                println!("Skipping layer_{i} due to config");
-                continue;
-            }
+        let dzi = parse_dzi(&path, cli.format).await?;
 
-            let scale = 0.5_f64.powi((i as i32) - 1);
-            let target_w = (dzi.width as f64 * scale).round() as u32;
-            let target_h = (dzi.height as f64 * scale).round() as u32;
+        if let (Some(client), Some(base_url)) = (client.as_ref(), cli.base_url.as_ref()) {
+            prefetch_tiles(client, base_url, &tex_dir, &dzi, cli.fetch_concurrency).await?;
+        }
 
-            compose_layer(
-                &layer.tiles,
-                i,
+        // Each `.dzi` group is independent, so hand the decode-bound composition
+        // off to a blocking thread and let the groups overlap instead of serialising
+        // them on the async runtime.
+        let tex_dir = tex_dir.clone();
+        let output_path = output_path.clone();
+        let enable_lower_layers = cli.enable_lower_layers;
+        let output_format = cli.output_format;
+        let quality = cli.quality;
+        let stream = cli.stream;
+        let stream_threshold = cli.stream_threshold;
+        let descriptor = path.to_string_lossy().to_string();
+        handles.push(tokio::task::spawn_blocking(move || {
+            compose_group(
+                &dzi,
                 &group,
+                &descriptor,
                 &tex_dir,
                 &output_path,
+                enable_lower_layers,
+                output_format,
+                quality,
+                background,
+                stream,
+                stream_threshold,
+            )
+        }));
+    }
+
+    let mut manifest = Manifest { groups: Vec::new() };
+    for handle in handles {
+        manifest.groups.push(handle.await??);
+    }
+
+    fs::create_dir_all(&output_path)?;
+    let manifest_path = output_path.join("manifest.json");
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+
+    println!("Assemble all cgs successfully!");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compose_group(
+    dzi: &DziFile,
+    group: &str,
+    descriptor: &str,
+    tex_dir: &Path,
+    output_path: &Path,
+    enable_lower_layers: bool,
+    output_format: OutputFormat,
+    quality: u8,
+    background: Rgba<u8>,
+    stream: bool,
+    stream_threshold: u64,
+) -> Result<GroupManifest> {
+    let mut layers = Vec::new();
+
+    for (i, layer) in dzi.layers.iter().enumerate() {
+        // `base_scale` folds in each backend's layer-0 convention (2× for malie,
+        // full-res for the standard backends) so the reported dims match the
+        // actual output instead of assuming malie's mip layout.
+        let scale = dzi.base_scale * 0.5_f64.powi(i as i32);
+        let target_w = (dzi.width as f64 * scale).round() as u32;
+        let target_h = (dzi.height as f64 * scale).round() as u32;
+
+        if i > 1 && !enable_lower_layers {
+            println!("Skipping layer_{i} due to config");
+            layers.push(LayerManifest {
+                layer_index: i,
                 target_w,
                 target_h,
-            )?;
+                scale,
+                output: None,
+                skipped: true,
+            });
+            continue;
         }
+
+        let produced = compose_layer(
+            &layer.tiles,
+            i,
+            group,
+            tex_dir,
+            output_path,
+            target_w,
+            target_h,
+            &dzi.tile_format,
+            output_format,
+            quality,
+            background,
+            stream,
+            stream_threshold,
+        )?;
+
+        let output = produced.then(|| {
+            format!("{group}/layer_{i}.{}", output_format.extension())
+        });
+        layers.push(LayerManifest {
+            layer_index: i,
+            target_w,
+            target_h,
+            scale,
+            output,
+            skipped: false,
+        });
     }
 
-    println!("Assemble all cgs successfully!");
-    Ok(())
+    Ok(GroupManifest {
+        group: group.to_string(),
+        descriptor: descriptor.to_string(),
+        width: dzi.width,
+        height: dzi.height,
+        layers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_zoom_tile_names_and_format() {
+        let dzi = DeepZoomXml
+            .parse(r#"<Image TileSize="256" Overlap="0" Format="jpg"><Size Width="512" Height="256"/></Image>"#)
+            .unwrap();
+        assert_eq!(dzi.tile_format, "jpg");
+        // Finest level is emitted first; a 512×256 image is a 2×1 tile grid.
+        let finest = &dzi.layers[0];
+        assert_eq!(finest.tiles[0], vec!["9/0_0".to_string(), "9/1_0".to_string()]);
+    }
+
+    #[test]
+    fn deep_zoom_rejects_overlap() {
+        let err = DeepZoomXml
+            .parse(r#"<Image TileSize="256" Overlap="1" Format="png"><Size Width="512" Height="256"/></Image>"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("Overlap"));
+    }
+
+    #[test]
+    fn zoomify_tiles_numbered_coarsest_first() {
+        let dzi = Zoomify
+            .parse(r#"<IMAGE_PROPERTIES WIDTH="512" HEIGHT="256" TILESIZE="256" NUMTILES="3" />"#)
+            .unwrap();
+        // Level 0 (coarsest) takes flat index 0, so the finest level's tiles are
+        // numbered after it: TileGroup0/1-0-0, TileGroup0/1-1-0.
+        let finest = &dzi.layers[0];
+        assert_eq!(
+            finest.tiles[0],
+            vec!["TileGroup0/1-0-0".to_string(), "TileGroup0/1-1-0".to_string()]
+        );
+        let coarsest = dzi.layers.last().unwrap();
+        assert_eq!(coarsest.tiles[0], vec!["TileGroup0/0-0-0".to_string()]);
+    }
+
+    #[test]
+    fn zoomify_rejects_wrong_num_tiles() {
+        let err = Zoomify
+            .parse(r#"<IMAGE_PROPERTIES WIDTH="512" HEIGHT="256" TILESIZE="256" NUMTILES="99" />"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("NUMTILES"));
+    }
+
+    #[test]
+    fn iiif_tile_region_names() {
+        let dzi = Iiif
+            .parse(r#"{"width":512,"height":256,"tiles":[{"width":256,"height":256,"scaleFactors":[1,2]}]}"#)
+            .unwrap();
+        // scaleFactor 1 is the full-resolution layer: a 2×1 grid of 256px regions.
+        let finest = &dzi.layers[0];
+        assert_eq!(finest.tiles[0][0], "0,0,256,256/256,/0/default");
+        assert_eq!(finest.tiles[0].len(), 2);
+        assert_eq!(finest.tiles.len(), 1);
+    }
+
+    #[test]
+    fn iiif_edge_column_requests_true_width() {
+        let dzi = Iiif
+            .parse(r#"{"width":500,"height":256,"tiles":[{"width":256,"height":256,"scaleFactors":[1]}]}"#)
+            .unwrap();
+        // The narrow right column must request its own 244px width, not a full
+        // tile the server would stretch.
+        let finest = &dzi.layers[0];
+        assert_eq!(finest.tiles[0][1], "256,0,244,256/244,/0/default");
+    }
 }